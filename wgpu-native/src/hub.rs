@@ -37,13 +37,82 @@ use crate::{
 };
 #[cfg(not(feature = "gfx-backend-gl"))]
 use crate::{InstanceHandle, InstanceId};
+use crate::BindGroupLayoutBinding;
 use lazy_static::lazy_static;
-#[cfg(feature = "local")]
-use parking_lot::Mutex;
-use parking_lot::RwLock;
 use vec_map::VecMap;
 
-use std::{fmt, ops, sync::Arc};
+use std::{collections::HashMap, fmt};
+
+/// `Send` natively, empty on single-threaded `wasm32`.
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+pub trait MaybeSend: Send {}
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+impl<T: Send + ?Sized> MaybeSend for T {}
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+pub trait MaybeSend {}
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+impl<T: ?Sized> MaybeSend for T {}
+
+/// `Sync` natively, empty on single-threaded `wasm32`.
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+pub trait MaybeSync: Sync {}
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+impl<T: Sync + ?Sized> MaybeSync for T {}
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+pub trait MaybeSync {}
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+impl<T: ?Sized> MaybeSync for T {}
+
+/// `Send + Sync` natively, empty on single-threaded `wasm32`.
+pub trait WasmNotSendSync: MaybeSend + MaybeSync {}
+impl<T: MaybeSend + MaybeSync + ?Sized> WasmNotSendSync for T {}
+
+// `cfg`-selected pointer and locks: atomic `Arc`/`parking_lot` natively, `Rc`/`RefCell` on bare `wasm32`.
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+use parking_lot::{Mutex, RwLock};
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
+use std::sync::Arc;
+
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+use self::single_threaded::{Mutex, RwLock};
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+use std::rc::Rc as Arc;
+
+/// Single-threaded `RefCell`-backed stand-ins mirroring `parking_lot`'s API.
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+mod single_threaded {
+    use std::cell::{Ref, RefCell, RefMut};
+
+    #[derive(Debug, Default)]
+    pub struct RwLock<T>(RefCell<T>);
+
+    impl<T> RwLock<T> {
+        pub fn new(value: T) -> Self {
+            RwLock(RefCell::new(value))
+        }
+        pub fn read(&self) -> Ref<T> {
+            self.0.borrow()
+        }
+        pub fn write(&self) -> RefMut<T> {
+            self.0.borrow_mut()
+        }
+    }
+
+    #[derive(Debug, Default)]
+    pub struct Mutex<T>(RefCell<T>);
+
+    impl<T> Mutex<T> {
+        pub fn new(value: T) -> Self {
+            Mutex(RefCell::new(value))
+        }
+        pub fn lock(&self) -> RefMut<T> {
+            self.0.borrow_mut()
+        }
+    }
+}
+
+/// Monotonically increasing queue submission index; deferred frees fence on it.
+pub type SubmissionIndex = usize;
 
 /// A simple structure to manage identities of objects.
 #[derive(Debug)]
@@ -88,80 +157,115 @@ impl<I: TypedId> IdentityManager<I> {
     }
 }
 
+/// Number of independently-locked shards backing each `Storage`.
+pub const SHARDS: usize = 16;
+
 #[derive(Debug)]
-pub struct Storage<T, I: TypedId> {
-    //TODO: consider concurrent hashmap?
-    map: VecMap<(T, Epoch)>,
-    _phantom: std::marker::PhantomData<I>,
+struct Shard<T: WasmNotSendSync> {
+    map: VecMap<(Arc<T>, Epoch)>,
 }
 
-impl<T, I: TypedId> ops::Index<I> for Storage<T, I> {
-    type Output = T;
-    fn index(&self, id: I) -> &T {
-        let (ref value, epoch) = self.map[id.index() as usize];
-        assert_eq!(epoch, id.epoch());
-        value
+impl<T: WasmNotSendSync> Default for Shard<T> {
+    fn default() -> Self {
+        Shard { map: VecMap::new() }
     }
 }
 
-impl<T, I: TypedId> ops::IndexMut<I> for Storage<T, I> {
-    fn index_mut(&mut self, id: I) -> &mut T {
-        let (ref mut value, epoch) = self.map[id.index() as usize];
-        assert_eq!(epoch, id.epoch());
-        value
+#[derive(Debug)]
+pub struct Storage<T: WasmNotSendSync, I: TypedId> {
+    // A concurrent map split into `SHARDS` independently-locked sub-maps.
+    shards: Vec<RwLock<Shard<T>>>,
+    _phantom: std::marker::PhantomData<I>,
+}
+
+impl<T: WasmNotSendSync, I: TypedId> Default for Storage<T, I> {
+    fn default() -> Self {
+        let mut shards = Vec::with_capacity(SHARDS);
+        for _ in 0..SHARDS {
+            shards.push(RwLock::new(Shard::default()));
+        }
+        Storage {
+            shards,
+            _phantom: std::marker::PhantomData,
+        }
     }
 }
 
-impl<T, I: TypedId> Storage<T, I> {
+impl<T: WasmNotSendSync, I: TypedId> Storage<T, I> {
+    fn shard(&self, index: Index) -> &RwLock<Shard<T>> {
+        &self.shards[index as usize % SHARDS]
+    }
+
+    // Slot within a shard's dense `VecMap`; ids `index` apart by `SHARDS`
+    // land in the same shard and are packed back-to-back here.
+    fn slot(index: Index) -> usize {
+        index as usize / SHARDS
+    }
+
     pub fn contains(&self, id: I) -> bool {
-        match self.map.get(id.index() as usize) {
+        let shard = self.shard(id.index()).read();
+        match shard.map.get(Self::slot(id.index())) {
             Some(&(_, epoch)) if epoch == id.epoch() => true,
             _ => false,
         }
     }
+
+    /// Clone out the `Arc` for `id` if present and the epoch matches.
+    pub fn get(&self, id: I) -> Option<Arc<T>> {
+        let shard = self.shard(id.index()).read();
+        match shard.map.get(Self::slot(id.index())) {
+            Some(&(ref value, epoch)) if epoch == id.epoch() => Some(Arc::clone(value)),
+            _ => None,
+        }
+    }
+
+    fn insert(&self, id: I, value: Arc<T>) {
+        let old = self
+            .shard(id.index())
+            .write()
+            .map
+            .insert(Self::slot(id.index()), (value, id.epoch()));
+        assert!(old.is_none());
+    }
+
+    fn remove(&self, id: I) -> Arc<T> {
+        let (value, epoch) = self
+            .shard(id.index())
+            .write()
+            .map
+            .remove(Self::slot(id.index()))
+            .unwrap();
+        assert_eq!(epoch, id.epoch());
+        value
+    }
 }
 
 #[derive(Debug)]
-pub struct Registry<T, I: TypedId> {
+pub struct Registry<T: WasmNotSendSync, I: TypedId> {
     #[cfg(feature = "local")]
     identity: Mutex<IdentityManager<I>>,
-    data: RwLock<Storage<T, I>>,
+    // Ids whose storage slot has been removed but whose index is not yet safe
+    // to recycle, each tagged with the submission that must complete first.
+    #[cfg(feature = "local")]
+    pending: Mutex<Vec<(I, SubmissionIndex)>>,
+    data: Storage<T, I>,
 }
 
-impl<T, I: TypedId> Default for Registry<T, I> {
+impl<T: WasmNotSendSync, I: TypedId> Default for Registry<T, I> {
     fn default() -> Self {
         Registry {
             #[cfg(feature = "local")]
             identity: Mutex::new(IdentityManager::default()),
-            data: RwLock::new(Storage {
-                map: VecMap::new(),
-                _phantom: std::marker::PhantomData,
-            }),
+            #[cfg(feature = "local")]
+            pending: Mutex::new(Vec::new()),
+            data: Storage::default(),
         }
     }
 }
 
-impl<T, I: TypedId> ops::Deref for Registry<T, I> {
-    type Target = RwLock<Storage<T, I>>;
-    fn deref(&self) -> &Self::Target {
-        &self.data
-    }
-}
-
-impl<T, I: TypedId> ops::DerefMut for Registry<T, I> {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.data
-    }
-}
-
-impl<T, I: TypedId + Copy> Registry<T, I> {
+impl<T: WasmNotSendSync, I: TypedId + Copy> Registry<T, I> {
     pub fn register(&self, id: I, value: T) {
-        let old = self
-            .data
-            .write()
-            .map
-            .insert(id.index() as usize, (value, id.epoch()));
-        assert!(old.is_none());
+        self.data.insert(id, Arc::new(value));
     }
 
     #[cfg(feature = "local")]
@@ -171,14 +275,113 @@ impl<T, I: TypedId + Copy> Registry<T, I> {
         id
     }
 
-    pub fn unregister(&self, id: I) -> T {
-        let (value, epoch) = self.data.write().map.remove(id.index() as usize).unwrap();
-        assert_eq!(epoch, id.epoch());
-        //Note: careful about the order here!
+    pub fn contains(&self, id: I) -> bool {
+        self.data.contains(id)
+    }
+
+    /// Clone out the resource behind `id`; panics if absent or epoch-stale.
+    pub fn get(&self, id: I) -> Arc<T> {
+        self.try_get(id).unwrap()
+    }
+
+    /// Like `get`, but `None` instead of panicking when absent or epoch-stale.
+    pub fn try_get(&self, id: I) -> Option<Arc<T>> {
+        self.data.get(id)
+    }
+
+    /// Remove the slot for `id` and hand back its `Arc`. The slot is freed now,
+    /// but the index is reclaimed only once `submission_index` completes (see
+    /// `cleanup`), so an in-flight id cannot be recycled early.
+    pub fn unregister(&self, id: I, submission_index: SubmissionIndex) -> Arc<T> {
+        let value = self.data.remove(id);
         #[cfg(feature = "local")]
-        self.identity.lock().free(id);
+        self.pending.lock().push((id, submission_index));
+        #[cfg(not(feature = "local"))]
+        let _ = submission_index;
         value
     }
+
+    /// Return indices whose submission has completed to the `IdentityManager`,
+    /// bumping epochs as they are reclaimed so stale `Id`s keep failing.
+    #[cfg(feature = "local")]
+    pub fn cleanup(&self, completed_submission: SubmissionIndex) {
+        let mut identity = self.identity.lock();
+        self.pending.lock().retain(|&(id, submission_index)| {
+            if submission_index <= completed_submission {
+                identity.free(id);
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Normalized, hashable form of a BGL descriptor; entries sorted by binding so
+/// reordered-but-equal descriptors dedup together.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct BindGroupLayoutDescriptorKey {
+    entries: Vec<BindGroupLayoutBinding>,
+}
+
+impl BindGroupLayoutDescriptorKey {
+    pub fn new(entries: &[BindGroupLayoutBinding]) -> Self {
+        let mut entries = entries.to_vec();
+        entries.sort_by_key(|binding| binding.binding);
+        BindGroupLayoutDescriptorKey { entries }
+    }
+}
+
+#[derive(Debug, Default)]
+struct BindGroupLayoutPoolInner {
+    ids: HashMap<BindGroupLayoutDescriptorKey, BindGroupLayoutId>,
+    // Per-id owner count plus the key to evict when it reaches zero.
+    refcounts: HashMap<BindGroupLayoutId, (BindGroupLayoutDescriptorKey, usize)>,
+}
+
+/// Deduplication pool for bind group layouts, keyed on the descriptor and
+/// refcounted per id so a layout lives until its last owner releases it.
+#[derive(Debug, Default)]
+pub struct BindGroupLayoutPool {
+    inner: Mutex<BindGroupLayoutPoolInner>,
+}
+
+impl BindGroupLayoutPool {
+    /// Return the id for `key`, creating it via `create` only on a miss. The
+    /// lookup, create and insert run under one lock, so racing duplicates
+    /// cannot both register; every caller bumps the owner count.
+    pub fn get_or_create<F>(&self, key: BindGroupLayoutDescriptorKey, create: F) -> BindGroupLayoutId
+    where
+        F: FnOnce() -> BindGroupLayoutId,
+    {
+        let mut inner = self.inner.lock();
+        if let Some(&id) = inner.ids.get(&key) {
+            inner.refcounts.get_mut(&id).unwrap().1 += 1;
+            return id;
+        }
+        let id = create();
+        inner.ids.insert(key.clone(), id);
+        inner.refcounts.insert(id, (key, 1));
+        id
+    }
+
+    /// Drop one owner of `id`, returning `true` once the last one is gone and
+    /// the storage slot should be unregistered.
+    pub fn release(&self, id: BindGroupLayoutId) -> bool {
+        let mut inner = self.inner.lock();
+        match inner.refcounts.get_mut(&id) {
+            Some(entry) if entry.1 > 1 => {
+                entry.1 -= 1;
+                false
+            }
+            Some(_) => {
+                let (key, _) = inner.refcounts.remove(&id).unwrap();
+                inner.ids.remove(&key);
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 #[derive(Default)]
@@ -191,6 +394,7 @@ pub struct Hub {
     pub devices: Arc<Registry<DeviceHandle, DeviceId>>,
     pub pipeline_layouts: Arc<Registry<PipelineLayoutHandle, PipelineLayoutId>>,
     pub bind_group_layouts: Arc<Registry<BindGroupLayoutHandle, BindGroupLayoutId>>,
+    pub bind_group_layout_pool: BindGroupLayoutPool,
     pub bind_groups: Arc<Registry<BindGroupHandle, BindGroupId>>,
     pub shader_modules: Arc<Registry<ShaderModuleHandle, ShaderModuleId>>,
     pub command_buffers: Arc<Registry<CommandBufferHandle, CommandBufferId>>,
@@ -202,12 +406,104 @@ pub struct Hub {
     pub textures: Arc<Registry<TextureHandle, TextureId>>,
     pub texture_views: Arc<Registry<TextureViewHandle, TextureViewId>>,
     pub samplers: Arc<Registry<SamplerHandle, SamplerId>>,
+
+    // Monotonic counter handing out the submission index that deferred frees
+    // are fenced against.
+    #[cfg(feature = "local")]
+    next_submission: Mutex<SubmissionIndex>,
+    // Highest submission the GPU has reported complete; reclamation fences on it.
+    #[cfg(feature = "local")]
+    last_completed: Mutex<SubmissionIndex>,
 }
 
+// `lazy_static` needs `Sync`; on single-threaded `wasm32` the `Rc`/`RefCell` hub isn't, so use a thread-local there.
+#[cfg(not(all(target_arch = "wasm32", not(target_feature = "atomics"))))]
 lazy_static! {
     pub static ref HUB: Hub = Hub::default();
 }
 
+#[cfg(all(target_arch = "wasm32", not(target_feature = "atomics")))]
+thread_local! {
+    pub static HUB: Hub = Hub::default();
+}
+
+#[cfg(feature = "local")]
+impl Hub {
+    /// Create, or deduplicate, a bind group layout from its descriptor entries.
+    /// On a hit the existing id is returned and `create` is not called; either
+    /// way the caller becomes an owner and must later pair with a destroy.
+    pub fn get_or_create_bind_group_layout<F>(
+        &self,
+        entries: &[BindGroupLayoutBinding],
+        create: F,
+    ) -> BindGroupLayoutId
+    where
+        F: FnOnce() -> BindGroupLayoutHandle,
+    {
+        let key = BindGroupLayoutDescriptorKey::new(entries);
+        self.bind_group_layout_pool
+            .get_or_create(key, || self.bind_group_layouts.register_local(create()))
+    }
+
+    /// Release one owner's claim on a bind group layout, unregistering the
+    /// storage slot (deferred to `submission_index`) only when the last owner
+    /// drops it. Returns the freed handle when that happens.
+    pub fn destroy_bind_group_layout(
+        &self,
+        id: BindGroupLayoutId,
+        submission_index: SubmissionIndex,
+    ) -> Option<Arc<BindGroupLayoutHandle>> {
+        if self.bind_group_layout_pool.release(id) {
+            Some(self.bind_group_layouts.unregister(id, submission_index))
+        } else {
+            None
+        }
+    }
+
+    /// Start a submit: recycle everything fenced against already-completed
+    /// submissions, then claim the next index for resources this submit retires.
+    pub fn track_submit(&self) -> SubmissionIndex {
+        self.cleanup(*self.last_completed.lock());
+        let mut next = self.next_submission.lock();
+        *next += 1;
+        *next
+    }
+
+    /// Submit-completion hook: record that the GPU finished `completed_submission`
+    /// and reclaim everything fenced against it or earlier.
+    pub fn complete_submission(&self, completed_submission: SubmissionIndex) {
+        let mut last = self.last_completed.lock();
+        if completed_submission > *last {
+            *last = completed_submission;
+        }
+        self.cleanup(completed_submission);
+    }
+
+    /// Reclaim ids unregistered while the GPU was still using them, now that
+    /// `completed_submission` has finished. Each registry returns its freed
+    /// indices to the `IdentityManager`.
+    pub fn cleanup(&self, completed_submission: SubmissionIndex) {
+        #[cfg(not(feature = "gfx-backend-gl"))]
+        self.instances.cleanup(completed_submission);
+        self.surfaces.cleanup(completed_submission);
+        self.adapters.cleanup(completed_submission);
+        self.devices.cleanup(completed_submission);
+        self.pipeline_layouts.cleanup(completed_submission);
+        self.bind_group_layouts.cleanup(completed_submission);
+        self.bind_groups.cleanup(completed_submission);
+        self.shader_modules.cleanup(completed_submission);
+        self.command_buffers.cleanup(completed_submission);
+        self.render_pipelines.cleanup(completed_submission);
+        self.compute_pipelines.cleanup(completed_submission);
+        self.render_passes.cleanup(completed_submission);
+        self.compute_passes.cleanup(completed_submission);
+        self.buffers.cleanup(completed_submission);
+        self.textures.cleanup(completed_submission);
+        self.texture_views.cleanup(completed_submission);
+        self.samplers.cleanup(completed_submission);
+    }
+}
+
 impl fmt::Debug for Hub {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Hub")